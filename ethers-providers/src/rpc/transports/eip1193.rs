@@ -1,9 +1,18 @@
 use super::common::JsonRpcError;
-use crate::{provider::ProviderError, JsonRpcClient};
+use crate::{provider::ProviderError, JsonRpcClient, PubsubClient};
 use async_trait::async_trait;
-use serde::{de::DeserializeOwned, Serialize};
+use ethers_core::types::{Address, U256};
+use futures_channel::mpsc;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::value::RawValue;
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    rc::Rc,
+};
 use thiserror::Error;
-use wasm_bindgen::{prelude::*, closure::Closure, JsValue};
+use wasm_bindgen::{prelude::*, closure::Closure, JsCast, JsValue};
+use wasm_bindgen_futures::spawn_local;
 use gloo_utils::format::JsValueSerdeExt;
 
 #[wasm_bindgen]
@@ -30,12 +39,109 @@ impl Request {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 // All attributes this library needs is thread unsafe.
 // But wasm itself is a single threaded... something.
 // To avoid problems with Send and Sync, all these parameters are
 // fetched whenever it is needed
-pub struct Eip1193 {}
+pub struct Eip1193 {
+    /// The specific provider object this instance talks to. `None` means "resolve
+    /// `window.ethereum` on every call", which keeps [`Eip1193::new`] working the way
+    /// it always has when only one wallet is injected.
+    provider: Option<Ethereum>,
+    subs: Rc<RefCell<Subscriptions>>,
+}
+
+impl std::fmt::Debug for Eip1193 {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Eip1193").finish()
+    }
+}
+
+#[derive(Default)]
+struct Subscriptions {
+    /// Senders for the notification stream of each active `eth_subscribe`, keyed by
+    /// the (hex-encoded) subscription id returned by the node.
+    channels: HashMap<U256, mpsc::UnboundedSender<Box<RawValue>>>,
+    /// The `"message"` listener installed on the provider, kept alive for as long as
+    /// this `Eip1193` lives so that it isn't dropped out from under the JS runtime.
+    listener: Option<Closure<dyn FnMut(JsValue)>>,
+}
+
+/// The payload of the `"message"` event the provider emits for `eth_subscription`
+/// notifications, as described in EIP-1193.
+#[derive(Debug, Deserialize)]
+struct SubscriptionMessage {
+    #[serde(rename = "type")]
+    kind: String,
+    data: SubscriptionData,
+}
+
+#[derive(Debug, Deserialize)]
+struct SubscriptionData {
+    subscription: U256,
+    result: Box<RawValue>,
+}
+
+/// A typed EIP-1193 provider event, as emitted via `window.ethereum.on(...)`.
+///
+/// See <https://eips.ethereum.org/EIPS/eip-1193#events>.
+#[derive(Debug, Clone)]
+pub enum Eip1193Event {
+    /// Emitted when the accounts available to the provider change.
+    AccountsChanged(Vec<Address>),
+    /// Emitted when the chain the provider is connected to changes.
+    ChainChanged(U256),
+    /// Emitted when the provider first becomes able to submit RPC requests.
+    Connect {
+        /// The chain the provider is now connected to.
+        chain_id: U256,
+    },
+    /// Emitted when the provider becomes unable to submit RPC requests.
+    Disconnect(JsonRpcError),
+    /// Emitted for any other provider message, e.g. `eth_subscription` notifications.
+    Message(serde_json::Value),
+}
+
+#[derive(Debug, Deserialize)]
+struct ConnectInfo {
+    #[serde(rename = "chainId")]
+    chain_id: U256,
+}
+
+/// Decodes the raw `JsValue` payload of a provider event into its typed form.
+fn decode_event(event: &str, payload: JsValue) -> Result<Eip1193Event, Eip1193Error> {
+    match event {
+        "accountsChanged" => Ok(Eip1193Event::AccountsChanged(payload.into_serde()?)),
+        "chainChanged" => Ok(Eip1193Event::ChainChanged(payload.into_serde()?)),
+        "connect" => {
+            let ConnectInfo { chain_id } = payload.into_serde()?;
+            Ok(Eip1193Event::Connect { chain_id })
+        }
+        "disconnect" => Ok(Eip1193Event::Disconnect(payload.into_serde()?)),
+        "message" => Ok(Eip1193Event::Message(payload.into_serde()?)),
+        _ => Err(Eip1193Error::JsParseError),
+    }
+}
+
+/// An active [`Eip1193::on_typed`] subscription.
+///
+/// Holds the [`Closure`] installed on the provider alive; dropping the handle calls
+/// `removeListener` so the JS-side listener is torn down instead of leaking for the
+/// lifetime of the page.
+pub struct SubscriptionHandle {
+    ethereum: Ethereum,
+    event: String,
+    closure: Option<Closure<dyn FnMut(JsValue)>>,
+}
+
+impl Drop for SubscriptionHandle {
+    fn drop(&mut self) {
+        if let Some(closure) = self.closure.take() {
+            self.ethereum.removeListener(&self.event, &closure);
+        }
+    }
+}
 
 #[derive(Error, Debug)]
 /// Error thrown when sending an HTTP request
@@ -53,11 +159,89 @@ pub enum Eip1193Error {
 
     #[error(transparent)]
     /// Thrown if the response could not be parsed
-    JsonRpcError(#[from] JsonRpcError),
+    JsonRpcError(JsonRpcError),
 
     #[error(transparent)]
     /// Serde JSON Error
     SerdeJson (#[from] serde_json::Error),
+
+    /// EIP-1193 error code 4001: the user rejected the request.
+    #[error("user rejected the request: {0}")]
+    UserRejected(JsonRpcError),
+
+    /// EIP-1193 error code 4100: the requested method or account has not been
+    /// authorized by the user.
+    #[error("unauthorized: {0}")]
+    Unauthorized(JsonRpcError),
+
+    /// EIP-1193 error code 4200: the provider does not support the requested method.
+    #[error("unsupported method: {0}")]
+    UnsupportedMethod(JsonRpcError),
+
+    /// EIP-1193 error code 4900: the provider is disconnected from all chains.
+    #[error("disconnected: {0}")]
+    Disconnected(JsonRpcError),
+
+    /// EIP-1193 error code 4901: the provider is disconnected from the specified chain.
+    #[error("chain disconnected: {0}")]
+    ChainDisconnected(JsonRpcError),
+}
+
+impl From<JsonRpcError> for Eip1193Error {
+    fn from(err: JsonRpcError) -> Self {
+        match err.code {
+            4001 => Eip1193Error::UserRejected(err),
+            4100 => Eip1193Error::Unauthorized(err),
+            4200 => Eip1193Error::UnsupportedMethod(err),
+            4900 => Eip1193Error::Disconnected(err),
+            4901 => Eip1193Error::ChainDisconnected(err),
+            _ => Eip1193Error::JsonRpcError(err),
+        }
+    }
+}
+
+/// The metadata a wallet announces about itself as part of EIP-6963 discovery.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Eip6963ProviderInfo {
+    pub uuid: String,
+    pub name: String,
+    pub rdns: String,
+    pub icon: String,
+}
+
+/// A wallet discovered via [`Eip1193::discover`], i.e. one that answered an
+/// EIP-6963 `eip6963:requestProvider` announcement request.
+///
+/// Wrap it with [`Eip1193::from_wallet`] to build a provider bound to this specific
+/// wallet rather than whatever happens to occupy `window.ethereum`.
+#[derive(Clone, Debug)]
+pub struct Eip1193Wallet {
+    /// The wallet's self-reported name, icon and reverse-DNS id.
+    pub info: Eip6963ProviderInfo,
+    provider: Ethereum,
+}
+
+impl Eip1193Wallet {
+    fn from_announce_detail(detail: JsValue) -> Result<Self, Eip1193Error> {
+        let info_js = js_sys::Reflect::get(&detail, &JsValue::from_str("info"))
+            .map_err(Eip1193Error::from)?;
+        let provider_js = js_sys::Reflect::get(&detail, &JsValue::from_str("provider"))
+            .map_err(Eip1193Error::from)?;
+
+        Ok(Eip1193Wallet { info: info_js.into_serde()?, provider: provider_js.unchecked_into() })
+    }
+}
+
+#[wasm_bindgen(inline_js = "
+export function discover_eip6963_js(callback) {
+    function onAnnounce(event) { callback(event.detail); }
+    window.addEventListener('eip6963:announceProvider', onAnnounce);
+    window.dispatchEvent(new Event('eip6963:requestProvider'));
+    window.removeEventListener('eip6963:announceProvider', onAnnounce);
+}
+")]
+extern "C" {
+    fn discover_eip6963_js(callback: &Closure<dyn FnMut(JsValue)>);
 }
 
 #[wasm_bindgen(inline_js = "export function get_provider_js() {return window.ethereum}")]
@@ -103,7 +287,13 @@ impl From<Eip1193Error> for ProviderError {
 
 impl From<JsValue> for Eip1193Error {
     fn from(src: JsValue) -> Self {
-        Eip1193Error::JsValueError(format!("{:?}", src))
+        // Rejections from `window.ethereum.request` are usually an object with
+        // `code`/`message`/`data`, per EIP-1193. Parse that shape before falling back
+        // to stringifying whatever was thrown.
+        match src.into_serde::<JsonRpcError>() {
+            Ok(err) => err.into(),
+            Err(_) => Eip1193Error::JsValueError(format!("{:?}", src)),
+        }
     }
 }
 
@@ -119,14 +309,14 @@ impl JsonRpcClient for Eip1193 {
         params: T,
     ) -> Result<R, Eip1193Error> {
 
-        let ethereum = Ethereum::default()?;
+        let ethereum = self.provider()?;
         let t_params = JsValue::from_serde(&params)?;
         let js_params = if t_params.is_null() { js_sys::Array::new().into() } else { t_params };
         let payload = Request::new(method.to_string(), js_params.clone());
         
 
         match ethereum.request(payload).await {
-            Ok(r) => Ok(r.into_serde().unwrap()),
+            Ok(r) => r.into_serde().map_err(|_| Eip1193Error::JsParseError),
             Err(e) => Err(e.into())
         }
     }
@@ -142,16 +332,297 @@ impl Eip1193 {
     }
 
     pub fn new() -> Self {
-        Eip1193 {}
+        Eip1193 { provider: None, subs: Rc::new(RefCell::new(Subscriptions::default())) }
+    }
+
+    /// Builds a provider bound to one specific wallet discovered via
+    /// [`Eip1193::discover`], instead of resolving `window.ethereum` on every call.
+    pub fn from_wallet(wallet: &Eip1193Wallet) -> Self {
+        Eip1193 {
+            provider: Some(wallet.provider.clone()),
+            subs: Rc::new(RefCell::new(Subscriptions::default())),
+        }
+    }
+
+    /// Discovers injected wallets via EIP-6963, so apps with more than one wallet
+    /// extension installed can let the user choose instead of racing for
+    /// `window.ethereum`.
+    pub fn discover() -> Vec<Eip1193Wallet> {
+        let wallets = Rc::new(RefCell::new(Vec::new()));
+        let collected = wallets.clone();
+        let callback: Closure<dyn FnMut(JsValue)> = Closure::wrap(Box::new(move |detail: JsValue| {
+            if let Ok(wallet) = Eip1193Wallet::from_announce_detail(detail) {
+                collected.borrow_mut().push(wallet);
+            }
+        }));
+
+        discover_eip6963_js(&callback);
+
+        wallets.borrow().clone()
+    }
+
+    /// Resolves the provider object this instance talks to: the one it was built
+    /// with, or `window.ethereum` if none was given.
+    fn provider(&self) -> Result<Ethereum, Eip1193Error> {
+        match &self.provider {
+            Some(ethereum) => Ok(ethereum.clone()),
+            None => Ethereum::default(),
+        }
     }
 
     pub fn on(self, event: &str, callback: Box<dyn FnMut(JsValue)>) -> Result<(), Eip1193Error>{
-        let ethereum = Ethereum::default()?;
+        let ethereum = self.provider()?;
         let closure = Closure::wrap(callback);
         ethereum.on(event, &closure);
         closure.forget();
         Ok(())
     }
 
+    /// Subscribes to a typed provider event (`"accountsChanged"`, `"chainChanged"`,
+    /// `"connect"`, `"disconnect"` or `"message"`), decoding the raw JS payload into
+    /// an [`Eip1193Event`] before invoking `callback`.
+    ///
+    /// Unlike [`Eip1193::on`], the returned [`SubscriptionHandle`] owns the listener:
+    /// drop it to unsubscribe instead of leaking the closure for the page's lifetime.
+    pub fn on_typed<F>(&self, event: &str, mut callback: F) -> Result<SubscriptionHandle, Eip1193Error>
+    where
+        F: FnMut(Eip1193Event) + 'static,
+    {
+        let ethereum = self.provider()?;
+        let event_name = event.to_string();
+        let closure: Closure<dyn FnMut(JsValue)> = Closure::wrap(Box::new(move |payload: JsValue| {
+            if let Ok(event) = decode_event(&event_name, payload) {
+                callback(event);
+            }
+        }));
+
+        ethereum.on(event, &closure);
+        Ok(SubscriptionHandle { ethereum, event: event.to_string(), closure: Some(closure) })
+    }
+
+    /// Installs the shared `"message"` listener used to dispatch `eth_subscription`
+    /// notifications to their matching channel, if it isn't installed already.
+    fn ensure_subscription_listener(&self) -> Result<(), Eip1193Error> {
+        if self.subs.borrow().listener.is_some() {
+            return Ok(());
+        }
+
+        let ethereum = self.provider()?;
+        // Weak, not `self.subs.clone()`: the closure below is stored inside
+        // `self.subs` itself (as `listener`), so a strong ref here would make
+        // `Subscriptions` own a `Closure` that owns an `Rc` back to itself --
+        // a cycle `Rc` can never collect. Upgrading on each call keeps the
+        // listener from outliving every other handle to this `Eip1193`.
+        let subs = Rc::downgrade(&self.subs);
+        let closure: Closure<dyn FnMut(JsValue)> = Closure::wrap(Box::new(move |msg: JsValue| {
+            let subs = match subs.upgrade() {
+                Some(subs) => subs,
+                None => return,
+            };
+            let msg: SubscriptionMessage = match msg.into_serde() {
+                Ok(msg) => msg,
+                Err(_) => return,
+            };
+            if msg.kind != "eth_subscription" {
+                return;
+            }
+            if let Some(tx) = subs.borrow().channels.get(&msg.data.subscription) {
+                let _ = tx.unbounded_send(msg.data.result);
+            }
+        }));
+
+        ethereum.on("message", &closure);
+        self.subs.borrow_mut().listener = Some(closure);
+        Ok(())
+    }
+
+}
+
+impl PubsubClient for Eip1193 {
+    type NotificationStream = mpsc::UnboundedReceiver<Box<RawValue>>;
+
+    fn subscribe<T: Into<U256>>(&self, id: T) -> Result<Self::NotificationStream, Eip1193Error> {
+        self.ensure_subscription_listener()?;
+        let (tx, rx) = mpsc::unbounded();
+        self.subs.borrow_mut().channels.insert(id.into(), tx);
+        Ok(rx)
+    }
+
+    fn unsubscribe<T: Into<U256>>(&self, id: T) -> Result<(), Eip1193Error> {
+        let id = id.into();
+        self.subs.borrow_mut().channels.remove(&id);
+
+        // `unsubscribe` isn't async, but telling the node to drop the subscription
+        // requires a round-trip `eth_unsubscribe` call, so dispatch it on the wasm
+        // microtask queue and let it complete in the background.
+        let this = self.clone();
+        spawn_local(async move {
+            let _ = this.request::<_, bool>("eth_unsubscribe", [id]).await;
+        });
+
+        Ok(())
+    }
+}
+
+/// The JSON-RPC methods [`Eip1193Fallback`] always routes to the wallet: anything
+/// that needs the user's accounts or a signature, plus everything under the
+/// `wallet_*` namespace (checked separately by prefix, since it's open-ended).
+fn default_wallet_only_methods() -> HashSet<String> {
+    [
+        "eth_requestAccounts",
+        "eth_accounts",
+        "eth_sendTransaction",
+        "eth_sign",
+        "personal_sign",
+        "eth_signTypedData",
+        "eth_signTypedData_v1",
+        "eth_signTypedData_v3",
+        "eth_signTypedData_v4",
+    ]
+    .into_iter()
+    .map(String::from)
+    .collect()
+}
+
+/// A [`JsonRpcClient`] that routes wallet-only methods (signing, sending
+/// transactions, account access) through an injected [`Eip1193`] wallet, and
+/// everything else through a secondary client such as a public HTTP endpoint --
+/// falling back to the wallet if that secondary client errors.
+///
+/// This avoids popping up the wallet's UI, or hitting its (often rate-limited) RPC
+/// backend, for every `eth_call`.
+#[derive(Debug, Clone)]
+pub struct Eip1193Fallback<C> {
+    wallet: Eip1193,
+    fallback: C,
+    wallet_only: Rc<HashSet<String>>,
+}
+
+impl<C> Eip1193Fallback<C> {
+    /// Builds a fallback transport using the default wallet-only method set.
+    pub fn new(wallet: Eip1193, fallback: C) -> Self {
+        Self::with_wallet_only_methods(wallet, fallback, default_wallet_only_methods())
+    }
+
+    /// Builds a fallback transport with a custom set of methods to route to the
+    /// wallet, in case the defaults don't match a given dapp's needs.
+    pub fn with_wallet_only_methods(wallet: Eip1193, fallback: C, wallet_only: HashSet<String>) -> Self {
+        Self { wallet, fallback, wallet_only: Rc::new(wallet_only) }
+    }
+
+    fn is_wallet_only(&self, method: &str) -> bool {
+        is_wallet_only_method(method, &self.wallet_only)
+    }
+}
+
+/// Whether `method` should be routed to the wallet rather than the fallback client.
+/// Pulled out of [`Eip1193Fallback::is_wallet_only`] so the classification logic can
+/// be unit tested without needing a concrete [`JsonRpcClient`] for `C`.
+fn is_wallet_only_method(method: &str, wallet_only: &HashSet<String>) -> bool {
+    method.starts_with("wallet_") || wallet_only.contains(method)
+}
+
+/// Error type returned by [`Eip1193Fallback`], wrapping whichever of its two
+/// clients produced the failure.
+#[derive(Error, Debug)]
+pub enum Eip1193FallbackError<C: JsonRpcClient> {
+    #[error(transparent)]
+    Wallet(#[from] Eip1193Error),
+
+    #[error(transparent)]
+    Fallback(C::Error),
+
+    /// The fallback client errored, and the wallet retry it triggered failed too.
+    /// Both reasons are kept since either one could be the actual cause.
+    #[error("fallback client failed ({fallback}), and the wallet retry also failed ({wallet})")]
+    Both { fallback: C::Error, wallet: Eip1193Error },
+}
+
+impl<C: JsonRpcClient> From<Eip1193FallbackError<C>> for ProviderError
+where
+    C::Error: Into<ProviderError>,
+{
+    fn from(src: Eip1193FallbackError<C>) -> Self {
+        match src {
+            Eip1193FallbackError::Wallet(e) => e.into(),
+            Eip1193FallbackError::Fallback(e) => e.into(),
+            Eip1193FallbackError::Both { fallback, wallet } => ProviderError::CustomError(format!(
+                "fallback client failed ({}), and the wallet retry also failed ({})",
+                fallback, wallet
+            )),
+        }
+    }
+}
+
+#[cfg_attr(target_arch = "wasm32", async_trait(?Send))]
+#[cfg_attr(not(target_arch = "wasm32"), async_trait)]
+impl<C> JsonRpcClient for Eip1193Fallback<C>
+where
+    C: JsonRpcClient,
+{
+    type Error = Eip1193FallbackError<C>;
+
+    /// Sends wallet-only methods to the injected wallet, everything else to the
+    /// fallback client -- retrying on the wallet if the fallback client errors.
+    async fn request<T: Serialize + Send + Sync, R: DeserializeOwned>(
+        &self,
+        method: &str,
+        params: T,
+    ) -> Result<R, Self::Error> {
+        if self.is_wallet_only(method) {
+            return self.wallet.request(method, params).await.map_err(Eip1193FallbackError::Wallet);
+        }
+
+        let params = serde_json::to_value(params)
+            .map_err(|e| Eip1193FallbackError::Wallet(Eip1193Error::SerdeJson(e)))?;
+
+        match self.fallback.request(method, params.clone()).await {
+            Ok(result) => Ok(result),
+            Err(fallback_err) => match self.wallet.request(method, params).await {
+                Ok(result) => Ok(result),
+                Err(wallet_err) => {
+                    Err(Eip1193FallbackError::Both { fallback: fallback_err, wallet: wallet_err })
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rpc_error(code: i64) -> JsonRpcError {
+        JsonRpcError { code, message: "test error".to_string(), data: None }
+    }
+
+    #[test]
+    fn maps_known_eip1193_codes() {
+        assert!(matches!(Eip1193Error::from(rpc_error(4001)), Eip1193Error::UserRejected(_)));
+        assert!(matches!(Eip1193Error::from(rpc_error(4100)), Eip1193Error::Unauthorized(_)));
+        assert!(matches!(Eip1193Error::from(rpc_error(4200)), Eip1193Error::UnsupportedMethod(_)));
+        assert!(matches!(Eip1193Error::from(rpc_error(4900)), Eip1193Error::Disconnected(_)));
+        assert!(matches!(Eip1193Error::from(rpc_error(4901)), Eip1193Error::ChainDisconnected(_)));
+    }
+
+    #[test]
+    fn falls_through_to_generic_json_rpc_error() {
+        assert!(matches!(Eip1193Error::from(rpc_error(-32000)), Eip1193Error::JsonRpcError(_)));
+    }
+
+    #[test]
+    fn routes_wallet_methods_to_the_wallet() {
+        let wallet_only = default_wallet_only_methods();
+        assert!(is_wallet_only_method("eth_sendTransaction", &wallet_only));
+        assert!(is_wallet_only_method("eth_requestAccounts", &wallet_only));
+        assert!(is_wallet_only_method("wallet_switchEthereumChain", &wallet_only));
+    }
+
+    #[test]
+    fn routes_everything_else_to_the_fallback_client() {
+        let wallet_only = default_wallet_only_methods();
+        assert!(!is_wallet_only_method("eth_call", &wallet_only));
+        assert!(!is_wallet_only_method("eth_getBalance", &wallet_only));
+    }
 }
 